@@ -3,18 +3,21 @@
 //! `--version`).  In the future, this module will simply provide helpers for `clap`, completely
 //! shedding itself of the current `getopts`-like APIs (or that I at least what I hope will occur).
 
-use clap::{App, Arg, ArgMatches};
+use clap::{App, AppSettings, Arg, ArgMatches, Shell};
 
 use std::borrow::ToOwned;
 use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::Write;
 use std::process;
 use std::rc::Rc;
 use std::str;
+use std::str::FromStr;
 
 pub struct HelpText<'a> {
     name: &'a str,
     version: Option<&'a str>,
-    syntax: Option<&'static str>,
+    syntax: Option<String>,
     summary: Option<&'a str>,
     long_help: Option<&'a str>,
 }
@@ -57,13 +60,11 @@ impl<'a> HelpTextBuilder<'a> {
         self
     }
 
-    // FIXME: leaks memory, but unsure how to fix otherwise as `clap` only allows Into<&str> for
-    //        `App::usage()`.
     pub fn build(self) -> HelpText<'a> {
         HelpText {
             name: self.name,
             version: self.version,
-            syntax: self.syntax.map(|val| &*Box::leak(val.into())),
+            syntax: self.syntax,
             summary: self.summary,
             long_help: self.long_help,
         }
@@ -100,6 +101,42 @@ impl<'a> Matches<'a> {
         self.inner.value_of(nm).map(ToOwned::to_owned)
     }
 
+    /// Return every value given for a multi-valued option (one registered via `optmulti`).
+    pub fn opt_strs(&self, nm: &str) -> Vec<String> {
+        let nm = self.convert_name(nm);
+        self.inner
+            .values_of(nm)
+            .map(|vals| vals.map(ToOwned::to_owned).collect())
+            .unwrap_or_default()
+    }
+
+    /// Return the number of times an option (registered via `optflagmulti`) was given.
+    pub fn opt_count(&self, nm: &str) -> usize {
+        let nm = self.convert_name(nm);
+        self.inner.occurrences_of(nm) as usize
+    }
+
+    /// Return `def` if the option was given without a value (e.g. an `optflagopt` with
+    /// `min_values(0)`), the given value if one was supplied, or `None` if the option was not
+    /// present at all.
+    pub fn opt_default(&self, nm: &str, def: &str) -> Option<String> {
+        let nm = self.convert_name(nm);
+        if self.inner.is_present(nm) {
+            Some(self.inner.value_of(nm).unwrap_or(def).to_owned())
+        } else {
+            None
+        }
+    }
+
+    /// Parse an option's value via `FromStr`, returning `Ok(None)` if the option was not given.
+    pub fn opt_get<T: FromStr>(&self, nm: &str) -> Result<Option<T>, T::Err> {
+        let nm = self.convert_name(nm);
+        match self.inner.value_of(nm) {
+            Some(val) => T::from_str(val).map(Some),
+            None => Ok(None),
+        }
+    }
+
     fn convert_name<'b>(&self, nm: &'b str) -> &'b str
     where
         'a: 'b,
@@ -112,25 +149,78 @@ impl<'a> Matches<'a> {
     }
 }
 
+/// Color behavior for rendered help/usage text; mirrors `clap`'s own color settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Colorize only when stdout is a terminal and `NO_COLOR` is unset.
+    Auto,
+    /// Always colorize.
+    Always,
+    /// Never colorize.
+    Never,
+}
+
+impl Default for ColorChoice {
+    fn default() -> Self {
+        ColorChoice::Auto
+    }
+}
+
 pub struct CoreOptions<'a: 'b, 'b> {
-    options: Option<App<'a, 'b>>,
     help_text: HelpText<'a>,
+    args: Vec<Arg<'a, 'b>>,
     short_to_long: Rc<HashMap<&'a str, &'a str>>,
+    // index into `args` of the most recently defined option, so that `conflicts`/`requires` can
+    // be chained right after the option they apply to
+    last_arg: Option<usize>,
+    wrap_help: bool,
+    color: ColorChoice,
 }
 
 impl<'a: 'b, 'b> CoreOptions<'a, 'b> {
     pub fn new(help_text: HelpText<'a>) -> Self {
-        let mut app = App::new(help_text.name);
-        if let Some(version) = help_text.version {
+        CoreOptions {
+            short_to_long: Rc::new(Default::default()),
+            help_text,
+            args: Vec::new(),
+            last_arg: None,
+            wrap_help: true,
+            color: ColorChoice::default(),
+        }
+    }
+
+    /// Control whether help and usage text is wrapped to the detected terminal width (falling
+    /// back to 80 columns when stdout isn't a terminal). Defaults to `true`.
+    pub fn wrap_help(&mut self, wrap: bool) -> &mut CoreOptions<'a, 'b> {
+        self.wrap_help = wrap;
+        self
+    }
+
+    /// Control whether help and usage text is colorized. Defaults to `ColorChoice::Auto`.
+    pub fn color(&mut self, choice: ColorChoice) -> &mut CoreOptions<'a, 'b> {
+        self.color = choice;
+        self
+    }
+
+    // `App`'s first lifetime (`'a`, shared with `Arg::name`) stays at `CoreOptions`'s own `'a`
+    // so the returned `ArgMatches<'a>` can live inside `Matches<'a>`; its second lifetime
+    // (`'b`, used for text like `usage`/`about`) only needs to live for this call, so it's
+    // free to borrow the owned `self.help_text.syntax` directly instead of leaking it.
+    fn build_app<'s>(&'s self) -> App<'a, 's>
+    where
+        'b: 's,
+    {
+        let mut app = App::new(self.help_text.name);
+        if let Some(version) = self.help_text.version {
             app = app.version(version);
         }
-        if let Some(syntax) = help_text.syntax {
-            app = app.usage(syntax);
+        if let Some(ref syntax) = self.help_text.syntax {
+            app = app.usage(syntax.as_str());
         }
-        if let Some(summary) = help_text.summary {
+        if let Some(summary) = self.help_text.summary {
             app = app.about(summary);
         }
-        if let Some(long_help) = help_text.long_help {
+        if let Some(long_help) = self.help_text.long_help {
             app = app.after_help(long_help);
         }
         app = app.arg(Arg::with_name("ARGS")
@@ -138,13 +228,61 @@ impl<'a: 'b, 'b> CoreOptions<'a, 'b> {
                       .multiple(true)
                       .hidden(true));
 
-        CoreOptions {
-            short_to_long: Rc::new(Default::default()),
-            help_text,
-            options: Some(app),
+        for arg in self.args.clone() {
+            app = app.arg(arg);
+        }
+
+        // `set_term_width` drives clap's own (unicode-width-aware) textwrap-based help
+        // formatter, so descriptions wrap correctly even with multibyte text.
+        if self.wrap_help {
+            app = app.set_term_width(terminal_width());
+        }
+
+        let colorize = match self.color {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => stdout_is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+        };
+        app = app.setting(if colorize {
+            AppSettings::ColorAlways
+        } else {
+            AppSettings::ColorNever
+        });
+
+        app
+    }
+
+    /// Resolve a short or long option name to its canonical long name, the same way
+    /// `Matches::convert_name` does.
+    fn resolve_name(&self, nm: &'a str) -> &'a str {
+        if nm.len() != 1 {
+            nm
+        } else {
+            *self.short_to_long.get(nm).unwrap_or(&nm)
         }
     }
 
+    /// Mark the most recently defined option as conflicting with `names` (each of which may be
+    /// a short or long spelling): if both are given, `parse`/`parse_safe` reports a usage error.
+    pub fn conflicts(&mut self, names: &'a [&'a str]) -> &mut CoreOptions<'a, 'b> {
+        let idx = self.last_arg.expect("conflicts() must follow an option definition");
+        let resolved: Vec<&'a str> = names.iter().map(|nm| self.resolve_name(nm)).collect();
+        let arg = self.args.remove(idx);
+        self.args.insert(idx, arg.conflicts_with_all(&resolved));
+        self
+    }
+
+    /// Mark the most recently defined option as requiring `names` (each of which may be a short
+    /// or long spelling): if it is given without all of `names`, `parse`/`parse_safe` reports a
+    /// usage error.
+    pub fn requires(&mut self, names: &'a [&'a str]) -> &mut CoreOptions<'a, 'b> {
+        let idx = self.last_arg.expect("requires() must follow an option definition");
+        let resolved: Vec<&'a str> = names.iter().map(|nm| self.resolve_name(nm)).collect();
+        let arg = self.args.remove(idx);
+        self.args.insert(idx, arg.requires_all(&resolved));
+        self
+    }
+
     // XXX: not sure if this is right
     // XXX: this does not allow hyphen values (at least for now) due to potential ambiguities
     pub fn optflagopt(
@@ -190,10 +328,10 @@ impl<'a: 'b, 'b> CoreOptions<'a, 'b> {
 
         self.optcommon(short, long, desc, |mut arg| {
             for &name in short_names {
-                arg = arg.alias(name);
+                arg = arg.visible_alias(name);
             }
             for &name in long_names {
-                arg = arg.alias(name);
+                arg = arg.visible_alias(name);
             }
             arg
         })
@@ -229,58 +367,175 @@ impl<'a: 'b, 'b> CoreOptions<'a, 'b> {
         self.optcommon(short_name, long_name, desc, |arg| arg.multiple(true).takes_value(true).value_name(hint))
     }
 
+    /// Like `optopt`, but the option must be given or `parse`/`parse_safe` reports a usage
+    /// error.
+    pub fn reqopt(
+        &mut self,
+        short_name: &'a str,
+        long_name: &'a str,
+        desc: &'a str,
+        hint: &'a str,
+    ) -> &mut CoreOptions<'a, 'b> {
+        self.optcommon(short_name, long_name, desc, |arg| arg.takes_value(true).value_name(hint).required(true))
+    }
+
+    /// Like `optopt`, but reject any value outside of `possible` at parse time.
+    pub fn optopt_possible(
+        &mut self,
+        short_name: &'a str,
+        long_name: &'a str,
+        desc: &'a str,
+        hint: &'a str,
+        possible: &'a [&'a str],
+    ) -> &mut CoreOptions<'a, 'b> {
+        self.optcommon(short_name, long_name, desc, |arg| {
+            arg.takes_value(true).value_name(hint).possible_values(possible)
+        })
+    }
+
+    /// Like `optopt`, but run `validator` against the value at parse time, rejecting it (with
+    /// the returned message) if validation fails.
+    pub fn optopt_validated<F>(
+        &mut self,
+        short_name: &'a str,
+        long_name: &'a str,
+        desc: &'a str,
+        hint: &'a str,
+        validator: F,
+    ) -> &mut CoreOptions<'a, 'b>
+    where
+        F: Fn(&str) -> Result<(), String> + Clone + 'static,
+    {
+        self.optcommon(short_name, long_name, desc, move |arg| {
+            let validator = validator.clone();
+            arg.takes_value(true).value_name(hint).validator(move |val| validator(&val))
+        })
+    }
+
     fn optcommon<F>(&mut self, short_name: &'a str, long_name: &'a str, desc: &'a str, func: F) -> &mut CoreOptions<'a, 'b>
     where
         F: Fn(Arg<'a, 'b>) -> Arg<'a, 'b>,
     {
-        let options = self.options.take();
-        self.options = options.map(|opts| {
-            let arg = if !long_name.is_empty() {
-                let long = Arg::with_name(long_name)
-                    .long(long_name);
-
-                if !short_name.is_empty() {
-                    Rc::get_mut(&mut self.short_to_long).unwrap().insert(short_name, long_name);
-
-                    long.short(short_name)
-                } else {
-                    long
-                }
-            } else if !short_name.is_empty() {
-                Arg::with_name(short_name)
-                    .short(short_name)
+        let arg = if !long_name.is_empty() {
+            let long = Arg::with_name(long_name)
+                .long(long_name);
+
+            if !short_name.is_empty() {
+                Rc::get_mut(&mut self.short_to_long).unwrap().insert(short_name, long_name);
+
+                long.short(short_name)
             } else {
-                // TODO: gracefully handle errors rather than panicking
-                panic!("option has neither a short nor a long name")
-            };
+                long
+            }
+        } else if !short_name.is_empty() {
+            Arg::with_name(short_name)
+                .short(short_name)
+        } else {
+            // TODO: gracefully handle errors rather than panicking
+            panic!("option has neither a short nor a long name")
+        };
 
-            opts.arg(func(arg.help(desc).allow_hyphen_values(true)))
-        });
+        self.args.push(func(arg.help(desc).allow_hyphen_values(true)));
+        self.last_arg = Some(self.args.len() - 1);
 
         self
     }
 
-    pub fn parse(&mut self, args: Vec<String>) -> Matches<'a> {
-        let matches = match self.options.clone().unwrap().get_matches_from_safe(&args[..]) {
+    /// Write a completion script for `shell` to `out`.
+    ///
+    /// `optflags` registers every extra short/long spelling of an option as a *visible* clap
+    /// alias (`Arg::visible_alias`), so the generated script completes on all of them in
+    /// addition to the option's canonical long and short names.
+    pub fn gen_completions(&self, shell: Shell, mut out: &mut dyn Write) {
+        let mut app = self.build_app();
+        let name = app.get_name().to_owned();
+        app.gen_completions_to(name, shell, &mut out);
+    }
+
+    /// Write completion scripts for bash, zsh, fish, elvish, and PowerShell into `out_dir`.
+    pub fn gen_completions_dir<T: Into<OsString>>(&self, out_dir: T) {
+        let out_dir = out_dir.into();
+        let name = self.build_app().get_name().to_owned();
+        for &shell in &[
+            Shell::Bash,
+            Shell::Zsh,
+            Shell::Fish,
+            Shell::Elvish,
+            Shell::PowerShell,
+        ] {
+            self.build_app().gen_completions(&name[..], shell, &out_dir);
+        }
+    }
+
+    /// Parse `args`, returning a `ParseError` instead of printing and exiting on failure so
+    /// callers can control where the output goes and what exit code is used.
+    pub fn parse_safe(&mut self, args: Vec<String>) -> Result<Matches<'a>, ParseError> {
+        let matches = match self.build_app().get_matches_from_safe(&args[..]) {
             Ok(m) => m,
             Err(ref f) if f.kind == clap::ErrorKind::HelpDisplayed || f.kind == clap::ErrorKind::VersionDisplayed => {
-                print!("{}", f);
-                process::exit(0);
+                return Err(ParseError::HelpOrVersion {
+                    text: f.to_string(),
+                    code: 0,
+                });
             }
             Err(f) => {
-                eprintln!("{}: {}", self.help_text.name, f);
-                process::exit(1);
+                return Err(ParseError::Usage {
+                    text: format!("{}: {}", self.help_text.name, f),
+                    code: 1,
+                });
             }
         };
 
         let free = matches.values_of("ARGS").map(|vals| vals.map(ToOwned::to_owned).collect()).unwrap_or_default();
 
-        Matches {
+        Ok(Matches {
             short_to_long: self.short_to_long.clone(),
             free,
             inner: matches,
+        })
+    }
+
+    pub fn parse(&mut self, args: Vec<String>) -> Matches<'a> {
+        match self.parse_safe(args) {
+            Ok(matches) => matches,
+            Err(ParseError::HelpOrVersion { text, code }) => {
+                print!("{}", text);
+                process::exit(code);
+            }
+            Err(ParseError::Usage { text, code }) => {
+                eprintln!("{}", text);
+                process::exit(code);
+            }
+        }
+    }
+}
+
+/// The outcome of a failed (or short-circuited) parse via [`CoreOptions::parse_safe`].
+#[derive(Debug, Clone)]
+pub enum ParseError {
+    /// `--help` or `--version` was requested; `text` is the formatted output to print (to
+    /// stdout) and `code` is the exit code to use (always `0`).
+    HelpOrVersion { text: String, code: i32 },
+    /// The arguments could not be parsed; `text` is the error message to print (to stderr) and
+    /// `code` is the exit code to use (always `1`).
+    Usage { text: String, code: i32 },
+}
+
+/// Query the column width of the controlling terminal, falling back to 80 when stdout isn't a
+/// terminal (e.g. output is piped).
+fn terminal_width() -> usize {
+    unsafe {
+        let mut winsize: libc::winsize = std::mem::zeroed();
+        if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut winsize) == 0 && winsize.ws_col > 0 {
+            return winsize.ws_col as usize;
         }
     }
+
+    80
+}
+
+fn stdout_is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) != 0 }
 }
 
 #[macro_export]